@@ -13,6 +13,7 @@ fn main() {
         _ => ()
     }
     let macros = cmacros::extract_macros(&header_src).unwrap();
+    let symbols = cmacros::build_symbol_table(&macros);
     let skipped_macros = [
         "SQLITE_EXTERN",
         "SQLITE_STATIC",
@@ -23,7 +24,7 @@ fn main() {
         if skipped_macros.contains(&&def.name[..]) {
             cmacros::TranslateAction::Skip
         } else {
-            cmacros::translate_macro(def)
+            cmacros::translate_macro(def, &symbols)
         }
     });
     let output_path = "sqlite3.rs";
@@ -31,7 +31,8 @@ fn main() {
         Err(err) => fatal_err!("Failed to extract macros from {}: {}", &input_path, err)
     };
 
-    let rust_src = cmacros::generate_rust_src(&macros, |def| cmacros::translate_macro(def));
+    let symbols = cmacros::build_symbol_table(&macros);
+    let rust_src = cmacros::generate_rust_src(&macros, |def| cmacros::translate_macro(def, &symbols));
     println!("{}", rust_src);
 }
 
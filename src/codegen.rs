@@ -0,0 +1,174 @@
+//! Renders macro translations through a `proc_macro2::TokenStream` - the
+//! same approach `syn`-based generators like `cpp_build` use for emitting
+//! Rust source. Constants are assembled with `quote!` from a name, a type
+//! and an expression that must each lex as Rust tokens; function-like
+//! macro translations are rendered as source text and then lexed. Lexing
+//! rejects eg. unbalanced delimiters or malformed literals, but whether
+//! the tokens form a valid Rust type, expression or item is not checked.
+//!
+//! Also provides a `Builder` for configuring a set of headers, a
+//! define-env and skip rules, and generating bindings from a crate's
+//! `build.rs`.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use proc_macro2::{Ident, Span, TokenStream};
+
+use {CMacro, ConstDecl, DefineEnv, FuncDecl, SymbolTable, TranslateAction};
+use {build_symbol_table, extract_macros_from_file_with_env, generate_func_src, translate_macro};
+
+/// Render a set of macro definitions to a `proc_macro2::TokenStream`,
+/// using `translate_fn` to decide how to map each macro, the same way
+/// `generate_rust_src` does for the plain-`String` API. Call `.to_string()`
+/// on the result to render it to Rust source text.
+///
+/// Returns `Err` rather than panicking if a translated macro's type or
+/// expression text does not lex as Rust tokens, so a single bad header
+/// can't abort a downstream crate's build. Text that lexes but is not a
+/// valid Rust type or expression is emitted as it is.
+pub fn generate_rust_tokens<TranslateFn>(defs: &[CMacro], translate_fn: TranslateFn) -> Result<TokenStream, String>
+where TranslateFn: Fn(&CMacro) -> TranslateAction {
+    let mut tokens = TokenStream::new();
+    for def in defs {
+        match translate_fn(def) {
+            TranslateAction::TypedConst(decl) => tokens.extend(try!(const_decl_tokens(&decl))),
+            TranslateAction::Func(decl) => tokens.extend(try!(func_decl_tokens(&decl))),
+            TranslateAction::Skip => {}
+        }
+    }
+    Ok(tokens)
+}
+
+fn const_decl_tokens(decl: &ConstDecl) -> Result<TokenStream, String> {
+    let name = Ident::new(&decl.name, Span::call_site());
+    let type_tokens: TokenStream = try!(decl.type_name.parse()
+        .map_err(|_| format!("'{}' is not a valid Rust type", decl.type_name)));
+    let expr_tokens: TokenStream = try!(decl.expr.parse()
+        .map_err(|_| format!("'{}' is not a valid Rust expression", decl.expr)));
+    Ok(quote! { pub const #name: #type_tokens = #expr_tokens; })
+}
+
+fn func_decl_tokens(decl: &FuncDecl) -> Result<TokenStream, String> {
+    let src = generate_func_src(decl);
+    src.parse()
+        .map_err(|_| format!("generated translation of '{}' was not valid Rust: {}", decl.name, src))
+}
+
+/// Configures a set of C headers to translate to Rust constants and
+/// function-like macro translations, for use from a crate's `build.rs`.
+///
+/// ```no_run
+/// // build.rs
+/// extern crate cmacros;
+///
+/// fn main() {
+///     cmacros::Builder::new()
+///         .header("vendor/widget.h")
+///         .include_dir("vendor")
+///         .define("WIDGET_V2", None)
+///         .skip("WIDGET_INTERNAL_ONLY")
+///         .write_to_out_dir("widget_bindings.rs")
+///         .unwrap();
+/// }
+/// ```
+pub struct Builder {
+    headers: Vec<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    env: DefineEnv,
+    skip: Vec<String>,
+    translate: Option<Box<Fn(&CMacro, &SymbolTable) -> TranslateAction>>
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            headers: vec![],
+            include_dirs: vec![],
+            env: DefineEnv::new(),
+            skip: vec![],
+            translate: None
+        }
+    }
+
+    /// Add a header file to translate.
+    pub fn header<P: Into<PathBuf>>(mut self, path: P) -> Builder {
+        self.headers.push(path.into());
+        self
+    }
+
+    /// Add a directory to search when resolving `#include <...>`
+    /// (and unresolved `#include "..."`) directives.
+    pub fn include_dir<P: Into<PathBuf>>(mut self, dir: P) -> Builder {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Define `name` in the preprocessor environment used to evaluate
+    /// `#if`/`#ifdef`/`#ifndef` blocks in the configured headers.
+    pub fn define(mut self, name: &str, value: Option<&str>) -> Builder {
+        self.env.insert(name.to_string(), value.map(|v| v.to_string()));
+        self
+    }
+
+    /// Skip translating the macro named `name`.
+    pub fn skip(mut self, name: &str) -> Builder {
+        self.skip.push(name.to_string());
+        self
+    }
+
+    /// Override how macros are mapped to Rust, in place of the default
+    /// `translate_macro`. Called with the macro being translated and the
+    /// symbol table built from every macro across the configured headers
+    /// (see `build_symbol_table`), so eg. a custom callback can still
+    /// resolve references to other macros.
+    pub fn translate<TranslateFn>(mut self, translate_fn: TranslateFn) -> Builder
+    where TranslateFn: Fn(&CMacro, &SymbolTable) -> TranslateAction + 'static {
+        self.translate = Some(Box::new(translate_fn));
+        self
+    }
+
+    /// Parse and translate the configured headers, returning the
+    /// generated Rust source.
+    pub fn generate(&self) -> Result<String, String> {
+        Ok(try!(self.generate_tokens()).to_string())
+    }
+
+    fn generate_tokens(&self) -> Result<TokenStream, String> {
+        let include_dirs: Vec<&Path> = self.include_dirs.iter().map(|dir| dir.as_path()).collect();
+
+        let mut macros: Vec<CMacro> = vec![];
+        for header in &self.headers {
+            macros.extend(try!(extract_macros_from_file_with_env(header, &include_dirs, &self.env)));
+        }
+
+        let symbols: SymbolTable = build_symbol_table(&macros);
+        let skip = &self.skip;
+        let translate = &self.translate;
+        generate_rust_tokens(&macros, |def| {
+            if skip.iter().any(|name| name == &def.name) {
+                TranslateAction::Skip
+            } else {
+                match *translate {
+                    Some(ref translate_fn) => translate_fn(def, &symbols),
+                    None => translate_macro(def, &symbols)
+                }
+            }
+        })
+    }
+
+    /// Generate Rust source for the configured headers and write it to
+    /// `file_name` inside `OUT_DIR`, for use from a `build.rs`.
+    pub fn write_to_out_dir(&self, file_name: &str) -> Result<(), String> {
+        let src = try!(self.generate());
+        let out_dir = try!(env::var("OUT_DIR")
+            .map_err(|_| "OUT_DIR is not set; write_to_out_dir must be called from a build.rs".to_string()));
+        let out_path = Path::new(&out_dir).join(file_name);
+        let mut out_file = try!(File::create(&out_path)
+            .map_err(|err| format!("failed to create '{}': {}", out_path.display(), err)));
+        out_file.write_all(src.as_bytes())
+            .map_err(|err| format!("failed to write '{}': {}", out_path.display(), err))
+    }
+}
@@ -0,0 +1,302 @@
+//! Tracking and evaluation of `#if`/`#ifdef`/`#ifndef`/`#elif`/`#else`/`#endif`
+//! conditional blocks, modeled on the conditional handling in the `makers`
+//! crate's `conditional.rs`.
+
+use std::collections::HashMap;
+
+use {CharStream, is_ident_char, parse_ident};
+
+/// Predefined symbols used to evaluate `#ifdef`/`#ifndef`/`#if` conditions.
+/// The value is `Some(text)` for macros defined with a body and `None` for
+/// macros defined with no body (eg. `#define FOO`).
+pub type DefineEnv = HashMap<String, Option<String>>;
+
+/// One `#if`/`#ifdef`/`#ifndef` ... `#endif` nesting level.
+struct Frame {
+    /// Whether this frame's currently-selected branch should be processed,
+    /// taking the state of all enclosing frames into account.
+    active: bool,
+    /// Whether any branch of this frame has evaluated to true yet.
+    taken: bool,
+    /// Whether an `#else` has already been seen for this frame.
+    seen_else: bool,
+}
+
+/// Stack of nested conditional blocks encountered while scanning a header.
+///
+/// `active()` reports whether a line at the current nesting level should be
+/// parsed. Malformed nesting (an `#else`/`#elif`/`#endif` with no matching
+/// `#if`, or more than one `#else` per block) is reported as an `Err`
+/// instead of panicking.
+pub struct ConditionalStack {
+    frames: Vec<Frame>,
+}
+
+impl ConditionalStack {
+    pub fn new() -> ConditionalStack {
+        ConditionalStack { frames: vec![] }
+    }
+
+    /// Whether every enclosing frame is active, ie. whether lines at the
+    /// current nesting level should be processed.
+    pub fn active(&self) -> bool {
+        self.frames.iter().all(|frame| frame.active)
+    }
+
+    fn enclosing_active(&self) -> bool {
+        let len = self.frames.len();
+        len < 2 || self.frames[..len - 1].iter().all(|frame| frame.active)
+    }
+
+    /// Push a new frame for an `#if`/`#ifdef`/`#ifndef` directive whose
+    /// condition evaluated to `cond`.
+    pub fn push_if(&mut self, cond: bool) {
+        let parent_active = self.active();
+        self.frames.push(Frame {
+            active: parent_active && cond,
+            taken: cond,
+            seen_else: false,
+        });
+    }
+
+    /// Handle an `#elif` directive whose condition evaluated to `cond`.
+    pub fn elif(&mut self, cond: bool) -> Result<(), String> {
+        let parent_active = self.enclosing_active();
+        match self.frames.last_mut() {
+            Some(frame) => {
+                if frame.seen_else {
+                    return Err("#elif found after #else".to_string());
+                }
+                if frame.taken {
+                    frame.active = false;
+                } else {
+                    frame.active = parent_active && cond;
+                    frame.taken = frame.active;
+                }
+                Ok(())
+            }
+            None => Err("#elif without matching #if".to_string()),
+        }
+    }
+
+    /// Handle an `#else` directive.
+    pub fn else_(&mut self) -> Result<(), String> {
+        let parent_active = self.enclosing_active();
+        match self.frames.last_mut() {
+            Some(frame) => {
+                if frame.seen_else {
+                    return Err("multiple #else directives for the same #if".to_string());
+                }
+                frame.seen_else = true;
+                frame.active = parent_active && !frame.taken;
+                frame.taken = true;
+                Ok(())
+            }
+            None => Err("#else without matching #if".to_string()),
+        }
+    }
+
+    /// Handle an `#endif` directive, popping exactly one frame.
+    pub fn pop(&mut self) -> Result<(), String> {
+        if self.frames.pop().is_none() {
+            Err("#endif without matching #if".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether any `#if` blocks are still open. Called at end-of-file to
+    /// detect a missing `#endif`.
+    pub fn unclosed(&self) -> bool {
+        !self.frames.is_empty()
+    }
+}
+
+/// Evaluate the expression following an `#if`/`#elif` directive.
+///
+/// Supports the `defined(FOO)` / `defined FOO` operator, integer literals
+/// (decimal or `0x`-prefixed hex), identifiers (resolved against `env` and
+/// treated as `0` if undefined or non-numeric), parentheses and the `!`,
+/// `&&`, `||`, `==`, `!=`, `<`, `>`, `<=`, `>=` operators.
+pub fn eval_if_expr(expr: &str, env: &DefineEnv) -> Result<bool, String> {
+    let mut input = CharStream::new(expr);
+    input.skip_whitespace();
+    let value = try!(parse_or_expr(&mut input, env));
+    input.skip_whitespace();
+    if !input.at_end() {
+        return Err(format!("Unexpected trailing tokens in #if expression: '{}'", input.tail()));
+    }
+    Ok(value != 0)
+}
+
+fn parse_or_expr(input: &mut CharStream, env: &DefineEnv) -> Result<i64, String> {
+    let mut value = try!(parse_and_expr(input, env));
+    loop {
+        input.skip_whitespace();
+        if input.consume("||") {
+            input.skip_whitespace();
+            let rhs = try!(parse_and_expr(input, env));
+            value = if value != 0 || rhs != 0 { 1 } else { 0 };
+        } else {
+            return Ok(value);
+        }
+    }
+}
+
+fn parse_and_expr(input: &mut CharStream, env: &DefineEnv) -> Result<i64, String> {
+    let mut value = try!(parse_cmp_expr(input, env));
+    loop {
+        input.skip_whitespace();
+        if input.consume("&&") {
+            input.skip_whitespace();
+            let rhs = try!(parse_cmp_expr(input, env));
+            value = if value != 0 && rhs != 0 { 1 } else { 0 };
+        } else {
+            return Ok(value);
+        }
+    }
+}
+
+fn parse_cmp_expr(input: &mut CharStream, env: &DefineEnv) -> Result<i64, String> {
+    let lhs = try!(parse_unary_expr(input, env));
+    input.skip_whitespace();
+    let op = if input.consume("==") {
+        Some("==")
+    } else if input.consume("!=") {
+        Some("!=")
+    } else if input.consume("<=") {
+        Some("<=")
+    } else if input.consume(">=") {
+        Some(">=")
+    } else if input.consume("<") {
+        Some("<")
+    } else if input.consume(">") {
+        Some(">")
+    } else {
+        None
+    };
+    match op {
+        Some(op) => {
+            input.skip_whitespace();
+            let rhs = try!(parse_unary_expr(input, env));
+            let result = match op {
+                "==" => lhs == rhs,
+                "!=" => lhs != rhs,
+                "<=" => lhs <= rhs,
+                ">=" => lhs >= rhs,
+                "<" => lhs < rhs,
+                ">" => lhs > rhs,
+                _ => unreachable!(),
+            };
+            Ok(if result { 1 } else { 0 })
+        }
+        None => Ok(lhs),
+    }
+}
+
+fn parse_unary_expr(input: &mut CharStream, env: &DefineEnv) -> Result<i64, String> {
+    input.skip_whitespace();
+    if input.consume("!") {
+        input.skip_whitespace();
+        let value = try!(parse_unary_expr(input, env));
+        Ok(if value == 0 { 1 } else { 0 })
+    } else {
+        parse_primary_expr(input, env)
+    }
+}
+
+fn parse_primary_expr(input: &mut CharStream, env: &DefineEnv) -> Result<i64, String> {
+    input.skip_whitespace();
+    if input.consume("(") {
+        input.skip_whitespace();
+        let value = try!(parse_or_expr(input, env));
+        input.skip_whitespace();
+        if !input.consume(")") {
+            return Err("Expected ')' in #if expression".to_string());
+        }
+        return Ok(value);
+    }
+
+    if input.tail().starts_with("defined") && !is_ident_char(input.peek("defined".len())) {
+        input.consume("defined");
+        input.skip_whitespace();
+        let parenthesized = input.consume("(");
+        input.skip_whitespace();
+        let name = parse_ident(input);
+        if name.len() == 0 {
+            return Err("Expected macro name after 'defined'".to_string());
+        }
+        let name = name.to_string();
+        input.skip_whitespace();
+        if parenthesized && !input.consume(")") {
+            return Err("Expected ')' after 'defined(...'".to_string());
+        }
+        return Ok(if env.contains_key(&name) { 1 } else { 0 });
+    }
+
+    if input.peek(0).is_digit(10) {
+        return parse_int_literal(input);
+    }
+
+    if is_ident_char(input.peek(0)) {
+        let name = parse_ident(input);
+        return Ok(match env.get(name) {
+            Some(&Some(ref value)) => value.trim().parse::<i64>().unwrap_or(0),
+            _ => 0,
+        });
+    }
+
+    Err(format!("Unexpected character '{}' in #if expression", input.peek(0)))
+}
+
+fn parse_int_literal(input: &mut CharStream) -> Result<i64, String> {
+    if input.consume("0x") || input.consume("0X") {
+        let digits = input.consume_while(|ch| ch.is_digit(16));
+        return i64::from_str_radix(digits, 16)
+            .map_err(|_| format!("Invalid hex literal '0x{}' in #if expression", digits));
+    }
+    let digits = input.consume_while(|ch| ch.is_digit(10));
+    digits.parse::<i64>()
+        .map_err(|_| format!("Invalid integer literal '{}' in #if expression", digits))
+}
+
+#[test]
+fn test_defined_operator() {
+    let mut env = DefineEnv::new();
+    env.insert("FOO".to_string(), None);
+    assert_eq!(eval_if_expr("defined(FOO)", &env), Ok(true));
+    assert_eq!(eval_if_expr("defined FOO", &env), Ok(true));
+    assert_eq!(eval_if_expr("!defined(BAR)", &env), Ok(true));
+    assert_eq!(eval_if_expr("defined(BAR)", &env), Ok(false));
+}
+
+#[test]
+fn test_integer_expr() {
+    let env = DefineEnv::new();
+    assert_eq!(eval_if_expr("1 && 0", &env), Ok(false));
+    assert_eq!(eval_if_expr("1 || 0", &env), Ok(true));
+    assert!(eval_if_expr("(1 + 0) || 0", &env).is_err());
+    assert_eq!(eval_if_expr("2 > 1 && 1 >= 1", &env), Ok(true));
+}
+
+#[test]
+fn test_ident_value() {
+    let mut env = DefineEnv::new();
+    env.insert("VERSION".to_string(), Some("2".to_string()));
+    assert_eq!(eval_if_expr("VERSION >= 2", &env), Ok(true));
+    assert_eq!(eval_if_expr("VERSION >= 3", &env), Ok(false));
+    assert_eq!(eval_if_expr("UNDEFINED_SYMBOL == 0", &env), Ok(true));
+}
+
+#[test]
+fn test_malformed_nesting() {
+    let mut stack = ConditionalStack::new();
+    assert!(stack.pop().is_err());
+    assert!(stack.else_().is_err());
+
+    stack.push_if(true);
+    assert!(stack.else_().is_ok());
+    assert!(stack.else_().is_err());
+    assert!(stack.pop().is_ok());
+    assert!(stack.pop().is_err());
+}
@@ -0,0 +1,465 @@
+//! Parsing and evaluation of C constant expressions, eg. the body of a
+//! `#define` such as `(1 << 4)` or `SOME_OTHER_MACRO + 1`. This mirrors the
+//! way the `makers` crate expands macro references before using them, and
+//! lets simple object-like macros be folded down to a single typed literal
+//! instead of being copied into the generated Rust source verbatim.
+
+use std::collections::{HashMap, HashSet};
+
+use {CharStream, is_ident_char, parse_ident};
+
+/// Maps macro names to their (unparsed) body text, for object-like macros
+/// only. Used to resolve identifier references found in other macro
+/// bodies.
+pub type SymbolTable = HashMap<String, String>;
+
+/// Build a symbol table of object-like macros (ie. macros with no
+/// arguments and a body) from a full set of parsed macros, for use when
+/// resolving the identifiers referenced by other macro bodies.
+pub fn build_symbol_table(defs: &[::CMacro]) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for def in defs {
+        if def.args.is_none() {
+            if let Some(ref body) = def.body {
+                symbols.insert(def.name.clone(), body.clone());
+            }
+        }
+    }
+    symbols
+}
+
+/// The result of folding a constant expression down to a single value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnaryOp {
+    Neg,
+    BitNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Shl, Shr, BitOr, BitAnd, BitXor, Add, Sub, Mul, Div, Rem
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Literal(Value),
+    Ident(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// Parse a C constant expression from `text`.
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut input = CharStream::new(text);
+    input.skip_whitespace();
+    let expr = try!(parse_bitor(&mut input));
+    input.skip_whitespace();
+    if !input.at_end() {
+        return Err(format!("Unexpected trailing tokens in expression: '{}'", input.tail()));
+    }
+    Ok(expr)
+}
+
+fn parse_bitor(input: &mut CharStream) -> Result<Expr, String> {
+    let mut expr = try!(parse_bitxor(input));
+    loop {
+        input.skip_whitespace();
+        if input.tail().starts_with("|") && !input.tail().starts_with("||") {
+            input.next();
+            input.skip_whitespace();
+            let rhs = try!(parse_bitxor(input));
+            expr = Expr::Binary(BinaryOp::BitOr, Box::new(expr), Box::new(rhs));
+        } else {
+            return Ok(expr);
+        }
+    }
+}
+
+fn parse_bitxor(input: &mut CharStream) -> Result<Expr, String> {
+    let mut expr = try!(parse_bitand(input));
+    loop {
+        input.skip_whitespace();
+        if input.consume("^") {
+            input.skip_whitespace();
+            let rhs = try!(parse_bitand(input));
+            expr = Expr::Binary(BinaryOp::BitXor, Box::new(expr), Box::new(rhs));
+        } else {
+            return Ok(expr);
+        }
+    }
+}
+
+fn parse_bitand(input: &mut CharStream) -> Result<Expr, String> {
+    let mut expr = try!(parse_shift(input));
+    loop {
+        input.skip_whitespace();
+        if input.tail().starts_with("&") && !input.tail().starts_with("&&") {
+            input.next();
+            input.skip_whitespace();
+            let rhs = try!(parse_shift(input));
+            expr = Expr::Binary(BinaryOp::BitAnd, Box::new(expr), Box::new(rhs));
+        } else {
+            return Ok(expr);
+        }
+    }
+}
+
+fn parse_shift(input: &mut CharStream) -> Result<Expr, String> {
+    let mut expr = try!(parse_additive(input));
+    loop {
+        input.skip_whitespace();
+        let op = if input.consume("<<") {
+            Some(BinaryOp::Shl)
+        } else if input.consume(">>") {
+            Some(BinaryOp::Shr)
+        } else {
+            None
+        };
+        match op {
+            Some(op) => {
+                input.skip_whitespace();
+                let rhs = try!(parse_additive(input));
+                expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+            },
+            None => return Ok(expr)
+        }
+    }
+}
+
+fn parse_additive(input: &mut CharStream) -> Result<Expr, String> {
+    let mut expr = try!(parse_term(input));
+    loop {
+        input.skip_whitespace();
+        let op = if input.consume("+") {
+            Some(BinaryOp::Add)
+        } else if input.consume("-") {
+            Some(BinaryOp::Sub)
+        } else {
+            None
+        };
+        match op {
+            Some(op) => {
+                input.skip_whitespace();
+                let rhs = try!(parse_term(input));
+                expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+            },
+            None => return Ok(expr)
+        }
+    }
+}
+
+fn parse_term(input: &mut CharStream) -> Result<Expr, String> {
+    let mut expr = try!(parse_unary(input));
+    loop {
+        input.skip_whitespace();
+        let op = if input.consume("*") {
+            Some(BinaryOp::Mul)
+        } else if input.consume("/") {
+            Some(BinaryOp::Div)
+        } else if input.consume("%") {
+            Some(BinaryOp::Rem)
+        } else {
+            None
+        };
+        match op {
+            Some(op) => {
+                input.skip_whitespace();
+                let rhs = try!(parse_unary(input));
+                expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+            },
+            None => return Ok(expr)
+        }
+    }
+}
+
+fn parse_unary(input: &mut CharStream) -> Result<Expr, String> {
+    input.skip_whitespace();
+    if input.consume("-") {
+        input.skip_whitespace();
+        let expr = try!(parse_unary(input));
+        Ok(Expr::Unary(UnaryOp::Neg, Box::new(expr)))
+    } else if input.consume("~") {
+        input.skip_whitespace();
+        let expr = try!(parse_unary(input));
+        Ok(Expr::Unary(UnaryOp::BitNot, Box::new(expr)))
+    } else {
+        parse_primary(input)
+    }
+}
+
+fn parse_primary(input: &mut CharStream) -> Result<Expr, String> {
+    input.skip_whitespace();
+    if input.consume("(") {
+        input.skip_whitespace();
+        let expr = try!(parse_bitor(input));
+        input.skip_whitespace();
+        if !input.consume(")") {
+            return Err("Expected ')' in expression".to_string());
+        }
+        return Ok(expr);
+    }
+
+    if input.peek(0) == '"' {
+        return parse_string_literal(input).map(|s| Expr::Literal(Value::Str(s)));
+    }
+
+    if input.peek(0) == '\'' {
+        return parse_char_literal(input).map(|ch| Expr::Literal(Value::Int(ch as i64)));
+    }
+
+    if input.peek(0).is_digit(10) {
+        return parse_numeric_literal(input);
+    }
+
+    if is_ident_char(input.peek(0)) {
+        let name = parse_ident(input);
+        return Ok(Expr::Ident(name.to_string()));
+    }
+
+    Err(format!("Unexpected character '{}' in expression", input.peek(0)))
+}
+
+fn parse_string_literal(input: &mut CharStream) -> Result<String, String> {
+    input.next(); // opening quote
+    let mut value = String::new();
+    loop {
+        if input.at_end() {
+            return Err("Unterminated string literal".to_string());
+        }
+        let ch = input.next();
+        if ch == '"' {
+            return Ok(value);
+        } else if ch == '\\' {
+            value.push(try!(unescape(input.next())));
+        } else {
+            value.push(ch);
+        }
+    }
+}
+
+fn parse_char_literal(input: &mut CharStream) -> Result<char, String> {
+    input.next(); // opening quote
+    let ch = input.next();
+    let value = if ch == '\\' {
+        try!(unescape(input.next()))
+    } else {
+        ch
+    };
+    if !input.consume("'") {
+        return Err("Unterminated char literal".to_string());
+    }
+    Ok(value)
+}
+
+fn unescape(ch: char) -> Result<char, String> {
+    Ok(match ch {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        other => return Err(format!("Unsupported escape sequence '\\{}'", other))
+    })
+}
+
+fn parse_numeric_literal(input: &mut CharStream) -> Result<Expr, String> {
+    let int_part = if input.consume("0x") || input.consume("0X") {
+        let digits = input.consume_while(|ch| ch.is_digit(16));
+        return i64::from_str_radix(digits, 16)
+            .map(|v| Expr::Literal(Value::Int(v)))
+            .map_err(|_| format!("Invalid hex literal '0x{}'", digits));
+    } else {
+        input.consume_while(|ch| ch.is_digit(10))
+    };
+
+    if input.peek(0) == '.' {
+        let mut text = int_part.to_string();
+        text.push(input.next());
+        text.push_str(input.consume_while(|ch| ch.is_digit(10)));
+        return text.parse::<f64>()
+            .map(|v| Expr::Literal(Value::Float(v)))
+            .map_err(|_| format!("Invalid floating-point literal '{}'", text));
+    }
+
+    // A leading zero is C octal notation, read the same way as in
+    // `literal::classify_literal` (decimal if it holds an '8' or '9').
+    if int_part.len() > 1 && int_part.starts_with('0') {
+        if let Ok(v) = i64::from_str_radix(int_part, 8) {
+            return Ok(Expr::Literal(Value::Int(v)));
+        }
+    }
+
+    int_part.parse::<i64>()
+        .map(|v| Expr::Literal(Value::Int(v)))
+        .map_err(|_| format!("Invalid integer literal '{}'", int_part))
+}
+
+/// Fold `expr` to a single value, resolving identifier references against
+/// `symbols` and detecting cyclic references.
+fn fold(expr: &Expr, symbols: &SymbolTable, visiting: &mut HashSet<String>) -> Result<Value, String> {
+    match *expr {
+        Expr::Literal(ref value) => Ok(value.clone()),
+        Expr::Ident(ref name) => {
+            if visiting.contains(name) {
+                return Err(format!("cyclic reference to macro '{}'", name));
+            }
+            let body = match symbols.get(name) {
+                Some(body) => body.clone(),
+                None => return Err(format!("reference to undefined macro '{}'", name))
+            };
+            visiting.insert(name.clone());
+            let referenced_expr = try!(parse_expr(&body));
+            let value = try!(fold(&referenced_expr, symbols, visiting));
+            visiting.remove(name);
+            Ok(value)
+        },
+        Expr::Unary(op, ref operand) => {
+            let value = try!(fold(operand, symbols, visiting));
+            match (op, value) {
+                (UnaryOp::Neg, Value::Int(v)) => Ok(Value::Int(-v)),
+                (UnaryOp::Neg, Value::Float(v)) => Ok(Value::Float(-v)),
+                (UnaryOp::BitNot, Value::Int(v)) => Ok(Value::Int(!v)),
+                (op, value) => Err(format!("operator {:?} cannot be applied to {:?}", op, value))
+            }
+        },
+        Expr::Binary(op, ref lhs, ref rhs) => {
+            let lhs = try!(fold(lhs, symbols, visiting));
+            let rhs = try!(fold(rhs, symbols, visiting));
+            apply_binary_op(op, lhs, rhs)
+        }
+    }
+}
+
+fn apply_binary_op(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match (lhs, rhs) {
+        (Value::Int(l), Value::Int(r)) => match op {
+            BinaryOp::Shl => l.checked_shl(r as u32).map(Value::Int)
+                .ok_or_else(|| format!("shift amount {} out of range for '{} << {}'", r, l, r)),
+            BinaryOp::Shr => l.checked_shr(r as u32).map(Value::Int)
+                .ok_or_else(|| format!("shift amount {} out of range for '{} >> {}'", r, l, r)),
+            BinaryOp::BitOr => Ok(Value::Int(l | r)),
+            BinaryOp::BitAnd => Ok(Value::Int(l & r)),
+            BinaryOp::BitXor => Ok(Value::Int(l ^ r)),
+            BinaryOp::Add => l.checked_add(r).map(Value::Int)
+                .ok_or_else(|| format!("integer overflow evaluating '{} + {}'", l, r)),
+            BinaryOp::Sub => l.checked_sub(r).map(Value::Int)
+                .ok_or_else(|| format!("integer overflow evaluating '{} - {}'", l, r)),
+            BinaryOp::Mul => l.checked_mul(r).map(Value::Int)
+                .ok_or_else(|| format!("integer overflow evaluating '{} * {}'", l, r)),
+            BinaryOp::Div => l.checked_div(r).map(Value::Int)
+                .ok_or_else(|| format!("division by zero evaluating '{} / {}'", l, r)),
+            BinaryOp::Rem => l.checked_rem(r).map(Value::Int)
+                .ok_or_else(|| format!("division by zero evaluating '{} % {}'", l, r)),
+        },
+        (Value::Float(l), Value::Float(r)) => apply_float_op(op, l, r),
+        (Value::Int(l), Value::Float(r)) => apply_float_op(op, l as f64, r),
+        (Value::Float(l), Value::Int(r)) => apply_float_op(op, l, r as f64),
+        (lhs, rhs) => Err(format!("operator {:?} cannot be applied to {:?} and {:?}", op, lhs, rhs))
+    }
+}
+
+fn apply_float_op(op: BinaryOp, l: f64, r: f64) -> Result<Value, String> {
+    match op {
+        BinaryOp::Add => Ok(Value::Float(l + r)),
+        BinaryOp::Sub => Ok(Value::Float(l - r)),
+        BinaryOp::Mul => Ok(Value::Float(l * r)),
+        BinaryOp::Div => Ok(Value::Float(l / r)),
+        op => Err(format!("operator {:?} is not defined for floating-point operands", op))
+    }
+}
+
+/// Render a folded value as a Rust literal, together with the narrowest
+/// Rust type that can hold it.
+fn render(value: &Value) -> (String, String) {
+    match *value {
+        Value::Int(v) => {
+            let type_name = if v >= i32::min_value() as i64 && v <= i32::max_value() as i64 {
+                "i32"
+            } else if v >= 0 && v <= u32::max_value() as i64 {
+                "u32"
+            } else {
+                "i64"
+            };
+            (v.to_string(), type_name.to_string())
+        },
+        Value::Float(v) => {
+            let mut text = v.to_string();
+            if !text.contains('.') {
+                text.push_str(".0");
+            }
+            (text, "f64".to_string())
+        },
+        Value::Str(ref s) => (format!("{:?}", s), "&'static str".to_string())
+    }
+}
+
+/// Parse and fold the body of an object-like macro to a Rust literal
+/// expression and its inferred type, resolving any references to other
+/// macros via `symbols`.
+///
+/// Returns `Err` if the body cannot be parsed, contains a reference to an
+/// undefined or cyclically-defined macro, or combines operands in a way
+/// that has no valid value (eg. shifting a string).
+pub fn eval_macro_body(body: &str, symbols: &SymbolTable) -> Result<(String, String), String> {
+    let expr = try!(parse_expr(body));
+    let mut visiting = HashSet::new();
+    let value = try!(fold(&expr, symbols, &mut visiting));
+    Ok(render(&value))
+}
+
+#[test]
+fn test_eval_bitwise_expr() {
+    let symbols = SymbolTable::new();
+    assert_eq!(eval_macro_body("(1 << 4)", &symbols), Ok(("16".to_string(), "i32".to_string())));
+    assert_eq!(eval_macro_body("0x10 | 0x20", &symbols), Ok(("48".to_string(), "i32".to_string())));
+    assert_eq!(eval_macro_body("~0", &symbols), Ok(("-1".to_string(), "i32".to_string())));
+}
+
+#[test]
+fn test_eval_resolves_macro_references() {
+    let mut symbols = SymbolTable::new();
+    symbols.insert("SOME_OTHER_MACRO".to_string(), "41".to_string());
+    assert_eq!(eval_macro_body("SOME_OTHER_MACRO + 1", &symbols), Ok(("42".to_string(), "i32".to_string())));
+}
+
+#[test]
+fn test_eval_detects_cyclic_reference() {
+    let mut symbols = SymbolTable::new();
+    symbols.insert("A".to_string(), "B + 1".to_string());
+    symbols.insert("B".to_string(), "A + 1".to_string());
+    assert!(eval_macro_body("A", &symbols).is_err());
+}
+
+#[test]
+fn test_eval_infers_wider_type_for_large_values() {
+    let symbols = SymbolTable::new();
+    assert_eq!(eval_macro_body("0xFFFFFFFF", &symbols), Ok(("4294967295".to_string(), "u32".to_string())));
+}
+
+#[test]
+fn test_eval_reports_div_by_zero_instead_of_panicking() {
+    let symbols = SymbolTable::new();
+    assert!(eval_macro_body("1 / 0", &symbols).is_err());
+    assert!(eval_macro_body("1 % 0", &symbols).is_err());
+}
+
+#[test]
+fn test_eval_reports_overflow_instead_of_panicking() {
+    let symbols = SymbolTable::new();
+    assert!(eval_macro_body("0x7FFFFFFFFFFFFFFF + 1", &symbols).is_err());
+}
+
+#[test]
+fn test_eval_reports_out_of_range_shift_instead_of_panicking() {
+    let symbols = SymbolTable::new();
+    assert!(eval_macro_body("1 << 100", &symbols).is_err());
+}
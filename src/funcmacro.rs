@@ -0,0 +1,384 @@
+//! Translation of function-like macro bodies, eg.
+//! `#define MIN(a,b) ((a)<(b)?(a):(b))`, to a Rust expression over the
+//! same parameter names. The C ternary `?:` operator is rewritten to an
+//! `if`/`else` expression; everything else (arithmetic, bitwise and
+//! comparison operators) maps directly onto the equivalent Rust syntax.
+
+use std::collections::HashSet;
+
+use {CharStream, is_ident_char, parse_ident};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnaryOp { Neg, Not }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Shl, Shr, BitOr, BitAnd, BitXor,
+    Add, Sub, Mul, Div, Rem,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    And, Or,
+}
+
+impl BinaryOp {
+    fn as_rust_str(&self) -> &'static str {
+        match *self {
+            BinaryOp::Shl => "<<", BinaryOp::Shr => ">>",
+            BinaryOp::BitOr => "|", BinaryOp::BitAnd => "&", BinaryOp::BitXor => "^",
+            BinaryOp::Add => "+", BinaryOp::Sub => "-", BinaryOp::Mul => "*",
+            BinaryOp::Div => "/", BinaryOp::Rem => "%",
+            BinaryOp::Eq => "==", BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<", BinaryOp::Le => "<=", BinaryOp::Gt => ">", BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&", BinaryOp::Or => "||",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    IntLiteral(i64),
+    Param(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+struct Parser<'a> {
+    input: CharStream<'a>,
+    params: &'a HashSet<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_ternary(&mut self) -> Result<Expr, String> {
+        let cond = try!(self.parse_or());
+        self.input.skip_whitespace();
+        if self.input.consume("?") {
+            self.input.skip_whitespace();
+            let then_branch = try!(self.parse_ternary());
+            self.input.skip_whitespace();
+            if !self.input.consume(":") {
+                return Err("Expected ':' in ternary expression".to_string());
+            }
+            self.input.skip_whitespace();
+            let else_branch = try!(self.parse_ternary());
+            Ok(Expr::Ternary(Box::new(cond), Box::new(then_branch), Box::new(else_branch)))
+        } else {
+            Ok(cond)
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_and());
+        loop {
+            self.input.skip_whitespace();
+            if self.input.consume("||") {
+                self.input.skip_whitespace();
+                let rhs = try!(self.parse_and());
+                expr = Expr::Binary(BinaryOp::Or, Box::new(expr), Box::new(rhs));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_equality());
+        loop {
+            self.input.skip_whitespace();
+            if self.input.consume("&&") {
+                self.input.skip_whitespace();
+                let rhs = try!(self.parse_equality());
+                expr = Expr::Binary(BinaryOp::And, Box::new(expr), Box::new(rhs));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_equality(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_relational());
+        loop {
+            self.input.skip_whitespace();
+            let op = if self.input.consume("==") {
+                Some(BinaryOp::Eq)
+            } else if self.input.consume("!=") {
+                Some(BinaryOp::Ne)
+            } else {
+                None
+            };
+            match op {
+                Some(op) => {
+                    self.input.skip_whitespace();
+                    let rhs = try!(self.parse_relational());
+                    expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+                },
+                None => return Ok(expr)
+            }
+        }
+    }
+
+    fn parse_relational(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_bitor());
+        loop {
+            self.input.skip_whitespace();
+            let op = if self.input.consume("<=") {
+                Some(BinaryOp::Le)
+            } else if self.input.consume(">=") {
+                Some(BinaryOp::Ge)
+            } else if self.input.tail().starts_with("<") && !self.input.tail().starts_with("<<") {
+                self.input.next();
+                Some(BinaryOp::Lt)
+            } else if self.input.tail().starts_with(">") && !self.input.tail().starts_with(">>") {
+                self.input.next();
+                Some(BinaryOp::Gt)
+            } else {
+                None
+            };
+            match op {
+                Some(op) => {
+                    self.input.skip_whitespace();
+                    let rhs = try!(self.parse_bitor());
+                    expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+                },
+                None => return Ok(expr)
+            }
+        }
+    }
+
+    fn parse_bitor(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_bitxor());
+        loop {
+            self.input.skip_whitespace();
+            if self.input.tail().starts_with("|") && !self.input.tail().starts_with("||") {
+                self.input.next();
+                self.input.skip_whitespace();
+                let rhs = try!(self.parse_bitxor());
+                expr = Expr::Binary(BinaryOp::BitOr, Box::new(expr), Box::new(rhs));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_bitxor(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_bitand());
+        loop {
+            self.input.skip_whitespace();
+            if self.input.consume("^") {
+                self.input.skip_whitespace();
+                let rhs = try!(self.parse_bitand());
+                expr = Expr::Binary(BinaryOp::BitXor, Box::new(expr), Box::new(rhs));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_bitand(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_shift());
+        loop {
+            self.input.skip_whitespace();
+            if self.input.tail().starts_with("&") && !self.input.tail().starts_with("&&") {
+                self.input.next();
+                self.input.skip_whitespace();
+                let rhs = try!(self.parse_shift());
+                expr = Expr::Binary(BinaryOp::BitAnd, Box::new(expr), Box::new(rhs));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_shift(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_additive());
+        loop {
+            self.input.skip_whitespace();
+            let op = if self.input.consume("<<") {
+                Some(BinaryOp::Shl)
+            } else if self.input.consume(">>") {
+                Some(BinaryOp::Shr)
+            } else {
+                None
+            };
+            match op {
+                Some(op) => {
+                    self.input.skip_whitespace();
+                    let rhs = try!(self.parse_additive());
+                    expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+                },
+                None => return Ok(expr)
+            }
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_term());
+        loop {
+            self.input.skip_whitespace();
+            let op = if self.input.consume("+") {
+                Some(BinaryOp::Add)
+            } else if self.input.consume("-") {
+                Some(BinaryOp::Sub)
+            } else {
+                None
+            };
+            match op {
+                Some(op) => {
+                    self.input.skip_whitespace();
+                    let rhs = try!(self.parse_term());
+                    expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+                },
+                None => return Ok(expr)
+            }
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.parse_unary());
+        loop {
+            self.input.skip_whitespace();
+            let op = if self.input.consume("*") {
+                Some(BinaryOp::Mul)
+            } else if self.input.consume("/") {
+                Some(BinaryOp::Div)
+            } else if self.input.consume("%") {
+                Some(BinaryOp::Rem)
+            } else {
+                None
+            };
+            match op {
+                Some(op) => {
+                    self.input.skip_whitespace();
+                    let rhs = try!(self.parse_unary());
+                    expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+                },
+                None => return Ok(expr)
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        self.input.skip_whitespace();
+        if self.input.consume("-") {
+            self.input.skip_whitespace();
+            let expr = try!(self.parse_unary());
+            Ok(Expr::Unary(UnaryOp::Neg, Box::new(expr)))
+        } else if self.input.consume("~") || self.input.consume("!") {
+            self.input.skip_whitespace();
+            let expr = try!(self.parse_unary());
+            Ok(Expr::Unary(UnaryOp::Not, Box::new(expr)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        self.input.skip_whitespace();
+        if self.input.consume("(") {
+            self.input.skip_whitespace();
+            let expr = try!(self.parse_ternary());
+            self.input.skip_whitespace();
+            if !self.input.consume(")") {
+                return Err("Expected ')' in macro body".to_string());
+            }
+            return Ok(expr);
+        }
+
+        if self.input.peek(0).is_digit(10) {
+            let digits = self.input.consume_while(|ch| ch.is_digit(10));
+            return digits.parse::<i64>()
+                .map(Expr::IntLiteral)
+                .map_err(|_| format!("Invalid integer literal '{}'", digits));
+        }
+
+        if is_ident_char(self.input.peek(0)) {
+            let name = parse_ident(&mut self.input).to_string();
+            if !self.params.contains(&name) {
+                return Err(format!("Reference to '{}', which is not a parameter of this macro", name));
+            }
+            return Ok(Expr::Param(name));
+        }
+
+        Err(format!("Unexpected character '{}' in macro body", self.input.peek(0)))
+    }
+}
+
+fn parse_body(body: &str, params: &HashSet<String>) -> Result<Expr, String> {
+    let mut parser = Parser { input: CharStream::new(body), params: params };
+    let expr = try!(parser.parse_ternary());
+    parser.input.skip_whitespace();
+    if !parser.input.at_end() {
+        return Err(format!("Unexpected trailing tokens in macro body: '{}'", parser.input.tail()));
+    }
+    Ok(expr)
+}
+
+fn is_pure_arithmetic(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Ternary(..) => false,
+        Expr::Unary(_, ref operand) => is_pure_arithmetic(operand),
+        Expr::Binary(op, ref lhs, ref rhs) => match op {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge |
+            BinaryOp::And | BinaryOp::Or => false,
+            _ => is_pure_arithmetic(lhs) && is_pure_arithmetic(rhs),
+        },
+        Expr::IntLiteral(_) | Expr::Param(_) => true,
+    }
+}
+
+fn render(expr: &Expr) -> String {
+    match *expr {
+        Expr::IntLiteral(v) => v.to_string(),
+        Expr::Param(ref name) => name.clone(),
+        Expr::Unary(UnaryOp::Neg, ref operand) => format!("-({})", render(operand)),
+        Expr::Unary(UnaryOp::Not, ref operand) => format!("!({})", render(operand)),
+        Expr::Binary(op, ref lhs, ref rhs) => format!("({}) {} ({})", render(lhs), op.as_rust_str(), render(rhs)),
+        Expr::Ternary(ref cond, ref then_branch, ref else_branch) => {
+            format!("if {} {{ {} }} else {{ {} }}", render(cond), render(then_branch), render(else_branch))
+        }
+    }
+}
+
+/// The result of translating a function-like macro body.
+pub struct TranslatedBody {
+    /// The translated body, as a Rust expression over `args`. A C ternary
+    /// `?:` is rewritten to an `if`/`else` expression.
+    pub body: String,
+    /// Whether the body is a pure arithmetic/bitwise expression, ie.
+    /// suitable for a `const fn` rather than requiring `macro_rules!`.
+    pub is_pure_arithmetic: bool,
+}
+
+/// Translate a function-like macro body to a Rust expression over `args`.
+///
+/// Returns `Err` if the body contains tokens this translator cannot map
+/// onto Rust, eg. a reference to something other than one of `args`.
+pub fn translate_body(body: &str, args: &[String]) -> Result<TranslatedBody, String> {
+    let params: HashSet<String> = args.iter().cloned().collect();
+    let expr = try!(parse_body(body, &params));
+    Ok(TranslatedBody {
+        body: render(&expr),
+        is_pure_arithmetic: is_pure_arithmetic(&expr),
+    })
+}
+
+#[test]
+fn test_translate_arithmetic_body() {
+    let args = vec!["a".to_string(), "b".to_string()];
+    let result = translate_body("(a) + (b)", &args).unwrap();
+    assert!(result.is_pure_arithmetic);
+    assert_eq!(result.body, "(a) + (b)");
+}
+
+#[test]
+fn test_translate_ternary_body() {
+    let args = vec!["a".to_string(), "b".to_string()];
+    let result = translate_body("((a)<(b)?(a):(b))", &args).unwrap();
+    assert!(!result.is_pure_arithmetic);
+    assert_eq!(result.body, "if (a) < (b) { a } else { b }");
+}
+
+#[test]
+fn test_translate_body_rejects_unknown_identifier() {
+    let args = vec!["a".to_string()];
+    assert!(translate_body("(a) + UNKNOWN", &args).is_err());
+}
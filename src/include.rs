@@ -0,0 +1,219 @@
+//! Transitive resolution of `#include` directives across header files,
+//! modeled on the include handling in the `makers` crate's `input.rs`.
+//!
+//! Angle-bracket includes (`#include <foo.h>`) are resolved against the
+//! supplied search directories; quote includes (`#include "foo.h"`) are
+//! resolved relative to the including file first, falling back to the
+//! search directories. Includes that cannot be resolved are a recoverable
+//! warning rather than a hard failure, so the rest of the header can
+//! still be extracted. Include cycles are broken with a set of visited
+//! (canonicalized) paths.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use {CMacro, CharStream, CHeaderLineIter, DefineEnv, Directive, conditional, process_directive_line};
+
+/// Parse `path`, together with any headers it `#include`s (searching
+/// `include_dirs` for angle-bracket includes), and return the combined
+/// set of macros with `CMacro::source` set to the file each came from.
+///
+/// See `extract_macros_from_file_with_env` for a version which seeds the
+/// `#if`/`#ifdef`/`#ifndef` evaluation with predefined symbols.
+pub fn extract_macros_from_file(path: &Path, include_dirs: &[&Path]) -> Result<Vec<CMacro>, String> {
+    extract_macros_from_file_with_env(path, include_dirs, &DefineEnv::new())
+}
+
+/// Like `extract_macros_from_file`, but evaluating `#if`/`#ifdef`/`#ifndef`
+/// blocks against `env` so that macros predefined by the caller (eg. via
+/// `Builder::define`) are visible across the whole include chain.
+pub fn extract_macros_from_file_with_env(path: &Path, include_dirs: &[&Path],
+                                          env: &DefineEnv) -> Result<Vec<CMacro>, String> {
+    let mut macros = vec![];
+    let mut visited = HashSet::new();
+    let mut env = env.clone();
+    try!(process_file(path, include_dirs, &mut visited, &mut env, &mut macros));
+    Ok(macros)
+}
+
+fn process_file(path: &Path, include_dirs: &[&Path], visited: &mut HashSet<PathBuf>,
+                 env: &mut DefineEnv, macros: &mut Vec<CMacro>) -> Result<(), String> {
+    let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if !visited.insert(canonical_path) {
+        // Already processed this file on the current include chain (or
+        // elsewhere); skip it rather than looping forever.
+        return Ok(());
+    }
+
+    let mut src = String::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut src))
+        .map_err(|err| format!("failed to read '{}': {}", path.display(), err)));
+
+    let source_name = path.to_string_lossy().into_owned();
+    let mut stack = conditional::ConditionalStack::new();
+    let line_iter = CHeaderLineIter{input: CharStream::new(&src)};
+    for line in line_iter {
+        match try!(process_directive_line(&line, &mut stack, env)) {
+            Some(Directive::Define(cmacro)) => macros.push(cmacro.with_source(&source_name)),
+            Some(Directive::Include(target)) => {
+                match resolve_include(&target, path, include_dirs) {
+                    Some(included_path) => {
+                        try!(process_file(&included_path, include_dirs, visited, env, macros));
+                    },
+                    None => {
+                        eprintln!("warning: could not resolve '#include {}' from '{}'",
+                                  target, path.display());
+                    }
+                }
+            },
+            None => {}
+        }
+    }
+
+    if stack.unclosed() {
+        return Err(format!("missing #endif at end of '{}'", path.display()));
+    }
+    Ok(())
+}
+
+/// Parse the target of an `#include` directive, returning its path text
+/// and whether it was written with angle brackets (`<...>`) rather than
+/// quotes (`"..."`).
+fn parse_include_target(text: &str) -> Option<(String, bool)> {
+    let mut input = CharStream::new(text);
+    if input.consume_char('"') {
+        Some((input.consume_while(|ch| ch != '"').to_string(), false))
+    } else if input.consume_char('<') {
+        Some((input.consume_while(|ch| ch != '>').to_string(), true))
+    } else {
+        None
+    }
+}
+
+fn resolve_include(text: &str, including_file: &Path, include_dirs: &[&Path]) -> Option<PathBuf> {
+    let (name, is_angle) = match parse_include_target(text) {
+        Some(target) => target,
+        None => return None
+    };
+
+    if !is_angle {
+        if let Some(parent) = including_file.parent() {
+            let candidate = parent.join(&name);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+        }
+    }
+
+    for dir in include_dirs {
+        let candidate = dir.join(&name);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+    }
+
+    None
+}
+
+#[test]
+fn test_parse_include_target() {
+    assert_eq!(parse_include_target("\"foo.h\""), Some(("foo.h".to_string(), false)));
+    assert_eq!(parse_include_target("<foo/bar.h>"), Some(("foo/bar.h".to_string(), true)));
+    assert_eq!(parse_include_target("foo.h"), None);
+}
+
+#[test]
+fn test_extract_macros_from_file_transitive() {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = temp_dir().join("cmacros_test_include_transitive");
+    fs::create_dir_all(&dir).unwrap();
+
+    let inner_path = dir.join("inner.h");
+    fs::File::create(&inner_path).unwrap().write_all(b"#define INNER 1\n").unwrap();
+
+    let outer_path = dir.join("outer.h");
+    fs::File::create(&outer_path).unwrap().write_all(
+        b"#include \"inner.h\"\n#define OUTER 2\n"
+    ).unwrap();
+
+    let macros = extract_macros_from_file(&outer_path, &[]).unwrap();
+    let names: Vec<&str> = macros.iter().map(|m| &m.name[..]).collect();
+    assert_eq!(names, vec!["INNER", "OUTER"]);
+    assert_eq!(macros[0].source, Some(inner_path.to_string_lossy().into_owned()));
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_extract_macros_from_file_breaks_cycles() {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = temp_dir().join("cmacros_test_include_cycle");
+    fs::create_dir_all(&dir).unwrap();
+
+    let a_path = dir.join("a.h");
+    let b_path = dir.join("b.h");
+    fs::File::create(&a_path).unwrap().write_all(b"#include \"b.h\"\n#define A 1\n").unwrap();
+    fs::File::create(&b_path).unwrap().write_all(b"#include \"a.h\"\n#define B 2\n").unwrap();
+
+    let macros = extract_macros_from_file(&a_path, &[]).unwrap();
+    let names: Vec<&str> = macros.iter().map(|m| &m.name[..]).collect();
+    assert_eq!(names, vec!["B", "A"]);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_extract_macros_from_file_with_env_sees_predefined_symbols() {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = temp_dir().join("cmacros_test_include_with_env");
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("header.h");
+    fs::File::create(&path).unwrap().write_all(
+        b"#ifdef MY_FLAG\n#define FEATURE_X 1\n#endif\n"
+    ).unwrap();
+
+    let mut env = DefineEnv::new();
+    env.insert("MY_FLAG".to_string(), None);
+
+    let macros = extract_macros_from_file_with_env(&path, &[], &env).unwrap();
+    let names: Vec<&str> = macros.iter().map(|m| &m.name[..]).collect();
+    assert_eq!(names, vec!["FEATURE_X"]);
+
+    assert_eq!(extract_macros_from_file(&path, &[]).unwrap().len(), 0);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_extract_macros_from_file_sees_earlier_defines_in_ifdef() {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = temp_dir().join("cmacros_test_include_chained_define");
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("header.h");
+    fs::File::create(&path).unwrap().write_all(
+        b"#define FOO 1\n#ifdef FOO\n#define FOO_SEEN 1\n#endif\n"
+    ).unwrap();
+
+    let macros = extract_macros_from_file(&path, &[]).unwrap();
+    let names: Vec<&str> = macros.iter().map(|m| &m.name[..]).collect();
+    assert_eq!(names, vec!["FOO", "FOO_SEEN"]);
+
+    fs::remove_dir_all(&dir).ok();
+}
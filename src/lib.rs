@@ -2,6 +2,22 @@
 //! '#define' macro definitions from C header files to corresponding
 //! Rust code for use with bindings to external libraries.
 
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+
+mod codegen;
+mod conditional;
+mod expr;
+mod funcmacro;
+mod include;
+mod literal;
+
+pub use codegen::{Builder, generate_rust_tokens};
+pub use conditional::DefineEnv;
+pub use expr::{SymbolTable, build_symbol_table};
+pub use include::{extract_macros_from_file, extract_macros_from_file_with_env};
+
 /// Macro definition parsed from a C header file
 #[derive(PartialEq, Debug)]
 pub struct CMacro {
@@ -10,7 +26,11 @@ pub struct CMacro {
     /// The arguments to the macro if it is a function-like macro
     pub args: Option<Vec<String>>,
     /// The text that the macro expands to
-    pub body: Option<String>
+    pub body: Option<String>,
+    /// The path of the header file this macro was parsed from, if known.
+    /// Only set when extracted via `extract_macros_from_file` or
+    /// `extract_macros_from_file_with_env`.
+    pub source: Option<String>
 }
 
 /// Attributes for a Rust constant definition
@@ -20,11 +40,24 @@ pub struct ConstDecl {
     pub expr: String
 }
 
+/// Attributes for a Rust translation of a function-like macro
+pub struct FuncDecl {
+    pub name: String,
+    pub args: Vec<String>,
+    pub body: String,
+    /// Whether `body` is a pure arithmetic/bitwise expression, suitable
+    /// for a `const fn` rather than requiring `macro_rules!`.
+    pub is_pure_arithmetic: bool
+}
+
 /// Specifies a transformation
 /// from a C macro definition to Rust code
 pub enum TranslateAction {
     /// Generate a constant with a specified type
     TypedConst(ConstDecl),
+    /// Generate a `macro_rules!` or `const fn` translation of a
+    /// function-like macro
+    Func(FuncDecl),
     /// Do not generate anything for this macro
     Skip
 }
@@ -32,31 +65,31 @@ pub enum TranslateAction {
 /// Provides a view of a string as a stream
 /// of chars which can be peeked, consumed etc.
 /// for use when writing simple parsers.
-struct CharStream<'a> {
+pub(crate) struct CharStream<'a> {
     input: &'a str,
     pos: usize
 }
 
 impl<'a> CharStream<'a> {
-    fn new(input: &str) -> CharStream {
+    pub(crate) fn new(input: &str) -> CharStream {
         CharStream{input: input, pos: 0}
     }
 
-    fn at_end(&self) -> bool {
+    pub(crate) fn at_end(&self) -> bool {
         self.pos >= self.input.len()
     }
 
-    fn peek(&self, offset: usize) -> char {
+    pub(crate) fn peek(&self, offset: usize) -> char {
         self.tail().chars().nth(offset).unwrap_or(0 as char)
     }
 
-    fn next(&mut self) -> char {
+    pub(crate) fn next(&mut self) -> char {
         let ch = self.peek(0);
         self.pos += 1;
         ch
     }
 
-    fn consume(&mut self, text: &str) -> bool {
+    pub(crate) fn consume(&mut self, text: &str) -> bool {
         if self.tail().starts_with(text) {
             self.pos += text.len();
             true
@@ -65,11 +98,11 @@ impl<'a> CharStream<'a> {
         }
     }
 
-    fn consume_char(&mut self, required: char) -> bool {
+    pub(crate) fn consume_char(&mut self, required: char) -> bool {
         self.consume_while(|ch| ch == required).len() > 0
     }
 
-    fn consume_while<Predicate>(&mut self, test: Predicate) -> &'a str 
+    pub(crate) fn consume_while<Predicate>(&mut self, test: Predicate) -> &'a str
     where Predicate: Fn(char) -> bool {
         let start_pos = self.pos;
         while test(self.peek(0)) {
@@ -78,11 +111,11 @@ impl<'a> CharStream<'a> {
         &self.input[start_pos..self.pos]
     }
 
-    fn skip_whitespace(&mut self) -> &str {
+    pub(crate) fn skip_whitespace(&mut self) -> &str {
         self.consume_while(|ch| ch.is_whitespace())
     }
 
-    fn tail(&self) -> &str {
+    pub(crate) fn tail(&self) -> &str {
         &self.input[self.pos..]
     }
 }
@@ -90,8 +123,8 @@ impl<'a> CharStream<'a> {
 /// Iterator over lines in a C header file.
 /// Lines with a trailing '\' are concatenated into
 /// single lines
-struct CHeaderLineIter<'a> {
-    input: CharStream<'a>
+pub(crate) struct CHeaderLineIter<'a> {
+    pub(crate) input: CharStream<'a>
 }
 
 impl<'a> Iterator for CHeaderLineIter<'a> {
@@ -122,7 +155,7 @@ impl<'a> Iterator for CHeaderLineIter<'a> {
     }
 }
 
-fn is_ident_char(ch: char) -> bool {
+pub(crate) fn is_ident_char(ch: char) -> bool {
     match ch {
         '0'...'9' | 'A'...'Z' | 'a'...'z' | '_' => true,
         _ => false
@@ -145,11 +178,11 @@ fn parse_arg_list(input: &mut CharStream) -> Result<Vec<String>,String> {
     }
 }
 
-fn parse_ident<'a>(input: &mut CharStream<'a>) -> &'a str {
+pub(crate) fn parse_ident<'a>(input: &mut CharStream<'a>) -> &'a str {
     input.consume_while(|ch| is_ident_char(ch))
 }
 
-fn parse_macro(input: &mut CharStream) -> Result<CMacro,String> {
+pub(crate) fn parse_macro(input: &mut CharStream) -> Result<CMacro,String> {
     let name = parse_ident(input);
     if name.len() == 0 {
         return Err(format!("Could not parse macro name from {}", input.tail()))
@@ -170,42 +203,126 @@ fn parse_macro(input: &mut CharStream) -> Result<CMacro,String> {
             Some(body.to_string())
         } else {
             None
-        }
+        },
+        source: None
     })
 }
 
-/// Parse the source for a C header and extract
-/// a list of macro definitions
-pub fn extract_macros(src: &str) -> Vec<CMacro> {
+/// Parse the source for a C header and extract a list of macro
+/// definitions, with no predefined symbols and no `#if` conditions
+/// assumed true.
+///
+/// See `extract_macros_with_env` for a version which evaluates
+/// `#if`/`#ifdef`/`#ifndef` blocks against a set of predefined symbols.
+pub fn extract_macros(src: &str) -> Result<Vec<CMacro>, String> {
+    extract_macros_with_env(src, &DefineEnv::new())
+}
+
+/// Parse the source for a C header and extract a list of macro
+/// definitions, evaluating `#if`/`#ifdef`/`#ifndef`/`#elif`/`#else`/
+/// `#endif` blocks against `env` so that macros in inactive branches are
+/// not returned.
+///
+/// Returns an `Err` if a conditional directive is malformed, including
+/// mismatched nesting (an `#else`/`#elif`/`#endif` with no matching
+/// `#if`, or a missing `#endif` at end of file).
+pub fn extract_macros_with_env(src: &str, env: &DefineEnv) -> Result<Vec<CMacro>, String> {
     let mut macros: Vec<CMacro> = vec![];
+    let mut stack = conditional::ConditionalStack::new();
+    let mut env = env.clone();
     let line_iter = CHeaderLineIter{input: CharStream::new(src)};
     for line in line_iter {
-        let mut macro_def = CharStream{input: &line, pos: 0};
-        if !macro_def.consume_char('#') {
-            // not a preprocessor line
-            continue;
+        match try!(process_directive_line(&line, &mut stack, &mut env)) {
+            Some(Directive::Define(cmacro)) => macros.push(cmacro),
+            // No filesystem context to resolve a '#include' against here;
+            // see `include::extract_macros_from_file` for that.
+            Some(Directive::Include(_)) | None => {}
         }
-        macro_def.skip_whitespace();
+    }
+    if stack.unclosed() {
+        return Err("missing #endif at end of file".to_string());
+    }
+    Ok(macros)
+}
 
-        if !macro_def.consume("define") || !macro_def.peek(0).is_whitespace() {
-            // not a #define
-            continue
-        }
-        macro_def.skip_whitespace();
+/// The effect of one line of header source on macro extraction, once any
+/// `#if`-family directive on that line has already been applied to
+/// `stack`/`env` by `process_directive_line`.
+pub(crate) enum Directive {
+    /// An active `#define NAME body`. `env` has already been updated so
+    /// that later `#ifdef`/`#ifndef` lines in the same file see `NAME` as
+    /// defined.
+    Define(CMacro),
+    /// An active `#include target`, with `target` the raw, unresolved
+    /// text following `#include` (eg. `"foo.h"` or `<foo.h>`).
+    Include(String),
+}
 
-        match parse_macro(&mut macro_def) {
-            Ok(cmacro) => macros.push(cmacro),
-            Err(err) => {
-                panic!("failed to parse {}: {}", &line, err)
-            }
+/// Dispatch one line of header source: update `stack`/`env` for a
+/// `#if`/`#ifdef`/`#ifndef`/`#elif`/`#else`/`#endif` directive, or return
+/// the `Directive` for an active `#define`/`#include` line. Shared by
+/// `extract_macros_with_env` and `include::process_file` so the two don't
+/// drift out of sync with each other.
+pub(crate) fn process_directive_line(line: &str, stack: &mut conditional::ConditionalStack,
+                                      env: &mut DefineEnv) -> Result<Option<Directive>, String> {
+    let mut directive = CharStream::new(line);
+    if !directive.consume_char('#') {
+        // not a preprocessor line
+        return Ok(None);
+    }
+    directive.skip_whitespace();
+
+    match parse_ident(&mut directive) {
+        "if" => {
+            directive.skip_whitespace();
+            let cond = try!(conditional::eval_if_expr(directive.tail(), env));
+            stack.push_if(cond);
+        },
+        "ifdef" => {
+            directive.skip_whitespace();
+            let name = parse_ident(&mut directive);
+            stack.push_if(env.contains_key(name));
+        },
+        "ifndef" => {
+            directive.skip_whitespace();
+            let name = parse_ident(&mut directive);
+            stack.push_if(!env.contains_key(name));
+        },
+        "elif" => {
+            directive.skip_whitespace();
+            let cond = try!(conditional::eval_if_expr(directive.tail(), env));
+            try!(stack.elif(cond));
+        },
+        "else" => try!(stack.else_()),
+        "endif" => try!(stack.pop()),
+        "include" if stack.active() => {
+            directive.skip_whitespace();
+            return Ok(Some(Directive::Include(directive.tail().to_string())));
+        },
+        "define" if stack.active() => {
+            directive.skip_whitespace();
+            let cmacro = try!(parse_macro(&mut directive).map_err(|err| {
+                format!("failed to parse '{}': {}", line, err)
+            }));
+            env.insert(cmacro.name.clone(), cmacro.body.clone());
+            return Ok(Some(Directive::Define(cmacro)));
+        },
+        _ => {
+            // Not a conditional directive or #define/#include, or inside
+            // an inactive branch: nothing to do for this line.
         }
     }
-    macros
+    Ok(None)
 }
 
 /// Generates Rust source based on a set of C macro definitions and
 /// a translation function which specifies how to map each macro to
-/// a corresponding Rust type
+/// a corresponding Rust type.
+///
+/// This produces human-readable, newline-separated source suitable for
+/// the example binaries in this crate. Code generated from a `build.rs`
+/// should use `generate_rust_tokens` or `Builder` instead, which validate
+/// the result by building it as a `proc_macro2::TokenStream`.
 pub fn generate_rust_src<TranslateFn>(defs: &[CMacro], translate_fn: TranslateFn) -> String
 where TranslateFn: Fn(&CMacro) -> TranslateAction {
     let decl_lines: Vec<String> = defs.iter()
@@ -214,6 +331,7 @@ where TranslateFn: Fn(&CMacro) -> TranslateAction {
                 TranslateAction::TypedConst(decl) => {
                     Some(format!("pub const {}: {} = {};", decl.name, decl.type_name, decl.expr))
                 },
+                TranslateAction::Func(decl) => Some(generate_func_src(&decl)),
                 TranslateAction::Skip => None
             }
         })
@@ -221,45 +339,111 @@ where TranslateFn: Fn(&CMacro) -> TranslateAction {
     decl_lines.connect("\n")
 }
 
+/// Render a function-like macro translation as either a `const fn`, for
+/// bodies which are pure arithmetic/bitwise expressions, or a
+/// `macro_rules!` expansion otherwise.
+pub(crate) fn generate_func_src(decl: &FuncDecl) -> String {
+    if !decl.is_pure_arithmetic {
+        let pattern = decl.args.iter()
+            .map(|arg| format!("${}:expr", arg))
+            .collect::<Vec<String>>()
+            .connect(", ");
+        let expansion = substitute_metavars(&decl.body, &decl.args);
+        format!("macro_rules! {} {{\n    ({}) => {{ {} }};\n}}", decl.name, pattern, expansion)
+    } else {
+        let params = decl.args.iter()
+            .map(|arg| format!("{}: i64", arg))
+            .collect::<Vec<String>>()
+            .connect(", ");
+        format!("pub const fn {}({}) -> i64 {{ {} }}", decl.name, params, decl.body)
+    }
+}
+
+/// Replace references to `args` in `body` with `macro_rules!` metavariable
+/// syntax (eg. `a` becomes `$a`), leaving everything else untouched.
+fn substitute_metavars(body: &str, args: &[String]) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut input = CharStream::new(body);
+    while !input.at_end() {
+        if is_ident_char(input.peek(0)) {
+            let ident = parse_ident(&mut input);
+            if args.iter().any(|arg| arg == ident) {
+                out.push('$');
+            }
+            out.push_str(ident);
+        } else {
+            out.push(input.next());
+        }
+    }
+    out
+}
+
 impl CMacro {
     pub fn new(name: &str, body: Option<&str>) -> CMacro {
-        CMacro{ name: name.to_string(), args: None, body: body.map(|s| s.to_string())}
+        CMacro{ name: name.to_string(), args: None, body: body.map(|s| s.to_string()), source: None }
     }
     pub fn new_with_args(name: &str, args: Vec<&str>, body: &str) -> CMacro {
         let arg_strings: Vec<String> = args.iter().map(|s| s.to_string()).collect();
-        CMacro{ name: name.to_string(), args: Some(arg_strings), body: Some(body.to_string()) }
+        CMacro{ name: name.to_string(), args: Some(arg_strings), body: Some(body.to_string()), source: None }
     }
-}
 
-/// Guess a suitable constant type for a macro
-/// based on the body of the macro
-pub fn guess_type(body: &str) -> &str {
-    if body.starts_with("\"") {
-        "&'static str"
-    } else if body.contains("0x") {
-        "u32"
-    } else {
-        "i32"
+    /// Return a copy of this macro with its `source` field set to `source`.
+    pub fn with_source(mut self, source: &str) -> CMacro {
+        self.source = Some(source.to_string());
+        self
     }
 }
 
-/// Guesses a suitable translation from a C macro
-/// definition to a Rust representation.
-/// 
-/// This is suitable for common simple cases such
-/// as macros which just expand to integer or
-/// string literals.
-pub fn translate_macro(def: &CMacro) -> TranslateAction {
-    if def.args.is_none() && def.body.is_some() {
-        let body = def.body.as_ref().unwrap().clone();
-        TranslateAction::TypedConst(ConstDecl{
-            name: def.name.clone(),
-            type_name: guess_type(&body).to_string(),
-            expr: body
-        })
-    } else {
-        TranslateAction::Skip
+/// Guesses a suitable translation from a C macro definition to a Rust
+/// representation.
+///
+/// Object-like macros whose body is a single literal are classified by
+/// `literal::classify_literal`, which understands C's integer bases and
+/// suffixes, floating-point and character literals. Otherwise the body
+/// is evaluated as a C constant expression, resolving references to
+/// other macros via `symbols` (see `build_symbol_table`), so that eg.
+/// `#define FLAG_B (1 << 4)` or `#define NEXT (FLAG_A + 1)` produce a
+/// typed Rust constant rather than an unparseable copy of the C source.
+/// Macros whose body is neither are skipped, rather than emitting a
+/// constant with a guessed (and potentially wrong) type.
+///
+/// Function-like macros are translated to a Rust expression over the
+/// same argument names (see `FuncDecl`), falling back to `Skip` if the
+/// body contains tokens this translator cannot map onto Rust.
+pub fn translate_macro(def: &CMacro, symbols: &SymbolTable) -> TranslateAction {
+    if let Some(ref args) = def.args {
+        return match def.body {
+            Some(ref body) => match funcmacro::translate_body(body, args) {
+                Ok(translated) => TranslateAction::Func(FuncDecl{
+                    name: def.name.clone(),
+                    args: args.clone(),
+                    body: translated.body,
+                    is_pure_arithmetic: translated.is_pure_arithmetic
+                }),
+                Err(_) => TranslateAction::Skip
+            },
+            None => TranslateAction::Skip
+        };
+    }
+
+    if let Some(ref body) = def.body {
+        if let Ok((value_expr, type_name)) = literal::classify_literal(body) {
+            return TranslateAction::TypedConst(ConstDecl{
+                name: def.name.clone(),
+                type_name: type_name,
+                expr: value_expr
+            });
+        }
+        if let Ok((value_expr, type_name)) = expr::eval_macro_body(body, symbols) {
+            return TranslateAction::TypedConst(ConstDecl{
+                name: def.name.clone(),
+                type_name: type_name,
+                expr: value_expr
+            });
+        }
     }
+
+    TranslateAction::Skip
 }
 
 #[test]
@@ -295,7 +479,7 @@ fn test_extract_macros() {
         CMacro::new("PRECEDING_SPACES", None),
         CMacro::new("SPACE_AFTER_HASH", None)
     ];
-    let actual_macros = extract_macros(src);
+    let actual_macros = extract_macros(src).unwrap();
 
     let expected_macro_names: Vec<&str> = expected_macros.iter().map(|m| &m.name[..]).collect();
     let actual_macro_names: Vec<&str> = actual_macros.iter().map(|m| &m.name[..]).collect();
@@ -306,6 +490,52 @@ fn test_extract_macros() {
     }
 }
 
+#[test]
+fn test_extract_macros_with_conditionals() {
+    let src = r"
+#define ALWAYS 1
+#ifdef WANT_FOO
+#define FOO 1
+#else
+#define NOT_FOO 1
+#endif
+#ifndef WANT_FOO
+#define NO_FOO 1
+#endif
+#if defined(WANT_FOO) && WANT_FOO >= 2
+#define FOO_V2 1
+#elif defined(WANT_FOO)
+#define FOO_V1 1
+#endif
+";
+    let mut env = DefineEnv::new();
+    env.insert("WANT_FOO".to_string(), Some("1".to_string()));
+
+    let macros = extract_macros_with_env(src, &env).unwrap();
+    let names: Vec<&str> = macros.iter().map(|m| &m.name[..]).collect();
+    assert_eq!(names, vec!["ALWAYS", "FOO", "FOO_V1"]);
+}
+
+#[test]
+fn test_extract_macros_sees_earlier_defines_in_ifdef() {
+    let src = "#define FOO 1\n#ifdef FOO\n#define FOO_SEEN 1\n#endif\n";
+    let macros = extract_macros(src).unwrap();
+    let names: Vec<&str> = macros.iter().map(|m| &m.name[..]).collect();
+    assert_eq!(names, vec!["FOO", "FOO_SEEN"]);
+}
+
+#[test]
+fn test_extract_macros_unclosed_if_is_error() {
+    let src = "#ifdef FOO\n#define BAR 1\n";
+    assert!(extract_macros(src).is_err());
+}
+
+#[test]
+fn test_extract_macros_unmatched_endif_is_error() {
+    let src = "#endif\n";
+    assert!(extract_macros(src).is_err());
+}
+
 #[test]
 fn test_generate_rust_src() {
     let macros: Vec<CMacro> = vec![
@@ -317,7 +547,7 @@ fn test_generate_rust_src() {
         if def.name.starts_with("USED") {
             TranslateAction::TypedConst(ConstDecl{
                 name: def.name.clone(),
-                type_name: guess_type(&def.body.as_ref().unwrap()).to_string(),
+                type_name: "i32".to_string(),
                 expr: def.body.as_ref().unwrap().clone()
             })
         } else {
@@ -329,3 +559,49 @@ fn test_generate_rust_src() {
         "pub const USED_CONST_2: i32 = 2;"
     ].connect("\n"))
 }
+
+#[test]
+fn test_translate_function_like_macros() {
+    let symbols = SymbolTable::new();
+
+    let min_macro = CMacro::new_with_args("MIN", vec!["a", "b"], "((a)<(b)?(a):(b))");
+    match translate_macro(&min_macro, &symbols) {
+        TranslateAction::Func(decl) => {
+            assert!(!decl.is_pure_arithmetic);
+            assert_eq!(decl.body, "if (a) < (b) { a } else { b }");
+        },
+        _ => panic!("expected MIN to translate to a Func")
+    }
+
+    let sum_macro = CMacro::new_with_args("SUM3", vec!["a", "b", "c"], "((a)+(b)+(c))");
+    match translate_macro(&sum_macro, &symbols) {
+        TranslateAction::Func(decl) => assert!(decl.is_pure_arithmetic),
+        _ => panic!("expected SUM3 to translate to a Func")
+    }
+
+    let src = generate_rust_src(&[min_macro], |def| translate_macro(def, &symbols));
+    assert_eq!(src, "macro_rules! MIN {\n    ($a:expr, $b:expr) => { if ($a) < ($b) { $a } else { $b } };\n}");
+}
+
+#[test]
+fn test_translate_macro_classifies_literals() {
+    let symbols = SymbolTable::new();
+
+    let octal_macro = CMacro::new("MODE", Some("0777"));
+    match translate_macro(&octal_macro, &symbols) {
+        TranslateAction::TypedConst(decl) => {
+            assert_eq!(decl.expr, "0o777");
+            assert_eq!(decl.type_name, "i32");
+        },
+        _ => panic!("expected MODE to translate to a TypedConst")
+    }
+
+    let suffixed_macro = CMacro::new("BIG", Some("1UL"));
+    match translate_macro(&suffixed_macro, &symbols) {
+        TranslateAction::TypedConst(decl) => {
+            assert_eq!(decl.expr, "1");
+            assert_eq!(decl.type_name, "u64");
+        },
+        _ => panic!("expected BIG to translate to a TypedConst")
+    }
+}
@@ -0,0 +1,241 @@
+//! A real lexer for C literals, replacing the three-line heuristic that
+//! `guess_type` used to be. Recognizes integer bases (decimal/hex/octal/
+//! binary), the `u`/`l`/`ll` integer suffixes (in any combination and
+//! case), floating-point literals (with an optional `f` suffix and/or
+//! exponent) and character literals, and maps each to the narrowest
+//! correct Rust type, re-emitting the value in valid Rust syntax (C
+//! suffixes stripped, octal `0777` becomes `0o777`).
+
+use CharStream;
+
+/// Classify `body` as a single C literal and translate it to Rust.
+///
+/// Returns `(rust_expr, rust_type)` on success, where `rust_expr` is
+/// valid Rust syntax for the value and `rust_type` is one of `i32`,
+/// `u32`, `i64`, `u64`, `f32`, `f64`, `char` or `&'static str`.
+///
+/// Returns `Err` if `body` is not (entirely) a single literal, eg.
+/// because it is a compound expression - see `eval_macro_body` for that
+/// case instead.
+pub fn classify_literal(body: &str) -> Result<(String, String), String> {
+    let text = body.trim();
+    if text.starts_with('"') {
+        return classify_string_literal(text);
+    }
+    if text.starts_with('\'') {
+        return classify_char_literal(text);
+    }
+    classify_numeric_literal(text)
+}
+
+fn classify_string_literal(text: &str) -> Result<(String, String), String> {
+    let mut input = CharStream::new(text);
+    input.next();
+    loop {
+        if input.at_end() {
+            return Err("Unterminated string literal".to_string());
+        }
+        let ch = input.next();
+        if ch == '\\' {
+            input.next();
+        } else if ch == '"' {
+            break;
+        }
+    }
+    if !input.at_end() {
+        return Err(format!("Unexpected trailing characters after string literal: '{}'", input.tail()));
+    }
+    Ok((text.to_string(), "&'static str".to_string()))
+}
+
+fn classify_char_literal(text: &str) -> Result<(String, String), String> {
+    let mut input = CharStream::new(text);
+    input.next();
+    let ch = input.next();
+    let value = if ch == '\\' {
+        try!(unescape(input.next()))
+    } else {
+        ch
+    };
+    if !input.consume("'") {
+        return Err("Unterminated char literal".to_string());
+    }
+    if !input.at_end() {
+        return Err(format!("Unexpected trailing characters after char literal: '{}'", input.tail()));
+    }
+    Ok((format!("{:?}", value), "char".to_string()))
+}
+
+fn unescape(ch: char) -> Result<char, String> {
+    Ok(match ch {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        other => return Err(format!("Unsupported escape sequence '\\{}'", other))
+    })
+}
+
+fn classify_numeric_literal(text: &str) -> Result<(String, String), String> {
+    let mut input = CharStream::new(text);
+
+    let (digits_text, radix, is_float_candidate) = if input.consume("0x") || input.consume("0X") {
+        (input.consume_while(|ch| ch.is_digit(16)).to_string(), 16, false)
+    } else if input.consume("0b") || input.consume("0B") {
+        (input.consume_while(|ch| ch == '0' || ch == '1').to_string(), 2, false)
+    } else {
+        let int_part = input.consume_while(|ch| ch.is_digit(10)).to_string();
+        (int_part, 10, true)
+    };
+
+    if digits_text.len() == 0 {
+        return Err(format!("Could not parse a numeric literal from '{}'", text));
+    }
+
+    // Floating-point literal: a decimal point and/or exponent following
+    // the leading digits.
+    if is_float_candidate && (input.peek(0) == '.' || input.peek(0) == 'e' || input.peek(0) == 'E') {
+        let mut mantissa = digits_text.clone();
+        if input.peek(0) == '.' {
+            mantissa.push(input.next());
+            mantissa.push_str(input.consume_while(|ch| ch.is_digit(10)));
+        }
+        let mut exponent = String::new();
+        if input.peek(0) == 'e' || input.peek(0) == 'E' {
+            exponent.push(input.next());
+            if input.peek(0) == '+' || input.peek(0) == '-' {
+                exponent.push(input.next());
+            }
+            exponent.push_str(input.consume_while(|ch| ch.is_digit(10)));
+        }
+        let is_f32 = input.consume("f") || input.consume("F");
+        let _ = input.consume("l") || input.consume("L");
+        if !input.at_end() {
+            return Err(format!("Unexpected trailing characters after float literal: '{}'", input.tail()));
+        }
+        let rust_text = format!("{}{}", mantissa, exponent);
+        let type_name = if is_f32 { "f32" } else { "f64" };
+        return Ok((rust_text, type_name.to_string()));
+    }
+
+    // Integer literal: an optional combination of `u`/`U` and `l`/`L` (or
+    // `ll`/`LL`) suffixes.
+    let mut is_unsigned = false;
+    let mut is_long = false;
+    loop {
+        if input.consume("u") || input.consume("U") {
+            is_unsigned = true;
+        } else if input.consume("l") || input.consume("L") {
+            is_long = true;
+        } else {
+            break;
+        }
+    }
+    if !input.at_end() {
+        return Err(format!("Unexpected trailing characters after integer literal: '{}'", input.tail()));
+    }
+
+    let looks_octal = radix == 10 && digits_text.starts_with('0') && digits_text.len() > 1;
+    // A leading zero with no `0x`/`0b` prefix is C octal notation. Fall
+    // back to treating it as decimal if it contains an '8' or '9', which
+    // cannot appear in an octal literal - and remember which radix it
+    // actually parsed as, so the prefix below matches the digits used.
+    let (value, is_octal) = if looks_octal {
+        match u64::from_str_radix(&digits_text, 8) {
+            Ok(value) => (value, true),
+            Err(_) => (try!(u64::from_str_radix(&digits_text, 10)
+                .map_err(|_| format!("Invalid numeric literal '{}'", digits_text))), false)
+        }
+    } else {
+        (try!(u64::from_str_radix(&digits_text, radix)
+            .map_err(|_| format!("Invalid base-{} literal '{}'", radix, digits_text))), false)
+    };
+
+    let prefix = match radix {
+        16 => "0x",
+        2 => "0b",
+        10 if is_octal => "0o",
+        _ => ""
+    };
+    let rust_digits = if prefix == "0o" {
+        // C octal (leading zero) -> Rust octal; the leading zero itself
+        // is not part of the Rust `0o` prefix.
+        digits_text.trim_start_matches('0')
+    } else {
+        &digits_text[..]
+    };
+    let rust_text = format!("{}{}", prefix, if rust_digits.len() == 0 { "0" } else { rust_digits });
+
+    // Unsuffixed literals widen through `unsigned int` before `long`, as
+    // C does for hex and octal constants (decimal constants take the same
+    // path here, although C widens them straight to `long`), so a value
+    // like `0xFFFFFFFF` is `u32` rather than `i64` - matching how
+    // `expr::render` infers a type for the same value inside a compound
+    // expression. Signed values too large for `i64` become `u64`.
+    let type_name = if is_unsigned {
+        if is_long || value > u32::max_value() as u64 { "u64" } else { "u32" }
+    } else if is_long {
+        if value <= i64::max_value() as u64 { "i64" } else { "u64" }
+    } else if value <= i32::max_value() as u64 {
+        "i32"
+    } else if value <= u32::max_value() as u64 {
+        "u32"
+    } else if value <= i64::max_value() as u64 {
+        "i64"
+    } else {
+        "u64"
+    };
+
+    Ok((rust_text, type_name.to_string()))
+}
+
+#[test]
+fn test_classify_integer_bases() {
+    assert_eq!(classify_literal("0x10"), Ok(("0x10".to_string(), "i32".to_string())));
+    assert_eq!(classify_literal("0b101"), Ok(("0b101".to_string(), "i32".to_string())));
+    assert_eq!(classify_literal("0777"), Ok(("0o777".to_string(), "i32".to_string())));
+    assert_eq!(classify_literal("42"), Ok(("42".to_string(), "i32".to_string())));
+}
+
+#[test]
+fn test_classify_integer_suffixes() {
+    assert_eq!(classify_literal("1UL"), Ok(("1".to_string(), "u64".to_string())));
+    assert_eq!(classify_literal("0xFFFFFFFFu"), Ok(("0xFFFFFFFF".to_string(), "u32".to_string())));
+    assert_eq!(classify_literal("5LL"), Ok(("5".to_string(), "i64".to_string())));
+}
+
+#[test]
+fn test_classify_invalid_octal_digit_falls_back_to_decimal() {
+    // '08' is not valid octal (no digit '8'), so it must be treated as
+    // the decimal value 8, not emitted as the invalid Rust '0o8'.
+    assert_eq!(classify_literal("08"), Ok(("08".to_string(), "i32".to_string())));
+    assert_eq!(classify_literal("09"), Ok(("09".to_string(), "i32".to_string())));
+}
+
+#[test]
+fn test_classify_unsuffixed_hex_widens_to_u32_before_i64() {
+    // Matches the C constant-promotion rules `expr::render` also follows:
+    // widen through `unsigned int` before `long`.
+    assert_eq!(classify_literal("0xFFFFFFFF"), Ok(("0xFFFFFFFF".to_string(), "u32".to_string())));
+}
+
+#[test]
+fn test_classify_float_literals() {
+    assert_eq!(classify_literal("3.14f"), Ok(("3.14".to_string(), "f32".to_string())));
+    assert_eq!(classify_literal("3.14"), Ok(("3.14".to_string(), "f64".to_string())));
+    assert_eq!(classify_literal("1e10"), Ok(("1e10".to_string(), "f64".to_string())));
+}
+
+#[test]
+fn test_classify_char_literal() {
+    assert_eq!(classify_literal("'A'"), Ok(("'A'".to_string(), "char".to_string())));
+    assert_eq!(classify_literal("'\\n'"), Ok(("'\\n'".to_string(), "char".to_string())));
+}
+
+#[test]
+fn test_classify_large_value_widens_type() {
+    assert_eq!(classify_literal("4294967296"), Ok(("4294967296".to_string(), "i64".to_string())));
+}